@@ -0,0 +1,222 @@
+//! Typed packet bodies, decoded/encoded according to a packet's TYPE flags.
+//!
+//! This is an optional layer on top of [`CodecStream`]/[`CodecSink`]'s raw
+//! `Box<[u8]>` API: [`BodyStream`]/[`BodySink`] interpret a packet's TYPE
+//! flags automatically, so callers working with string or JSON payloads
+//! don't have to re-inspect `Metadata` and parse by hand.
+
+use std::pin::Pin;
+use std::task::Poll::{Pending, Ready};
+use std::task::{Poll, Waker};
+
+use futures_core::stream::TryStream;
+use futures_io::{AsyncRead, AsyncWrite};
+use futures_sink::Sink;
+
+use crate::error::CodecError;
+use crate::{CodecSink, CodecStream, Metadata, TYPE, TYPE_BINARY, TYPE_JSON, TYPE_STRING};
+
+/// A packet payload, typed according to its TYPE flags.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Body {
+    /// An opaque byte string (the `TYPE_BINARY` flags).
+    Binary(Box<[u8]>),
+    /// A UTF-8 string (the `TYPE_STRING` flags).
+    String(String),
+    /// A JSON value (the `TYPE_JSON` flags).
+    Json(serde_json::Value),
+}
+
+impl Body {
+    fn type_flag(&self) -> u8 {
+        match self {
+            Body::Binary(_) => TYPE_BINARY,
+            Body::String(_) => TYPE_STRING,
+            Body::Json(_) => TYPE_JSON,
+        }
+    }
+
+    fn decode(data: Box<[u8]>, metadata: &Metadata) -> Result<Body, CodecError> {
+        if metadata.is_string_packet() {
+            Ok(Body::String(String::from_utf8(data.into_vec())?))
+        } else if metadata.is_json_packet() {
+            Ok(Body::Json(serde_json::from_slice(&data)?))
+        } else {
+            Ok(Body::Binary(data))
+        }
+    }
+
+    fn into_bytes(self) -> Result<Box<[u8]>, CodecError> {
+        match self {
+            Body::Binary(data) => Ok(data),
+            Body::String(s) => Ok(s.into_bytes().into_boxed_slice()),
+            Body::Json(v) => Ok(serde_json::to_vec(&v)?.into_boxed_slice()),
+        }
+    }
+}
+
+/// This stream decodes pairs of [`Body`] and `Metadata` from the wrapped
+/// `AsyncRead` of type `R`.
+///
+/// A thin [`CodecStream`] adapter: the only thing it adds is turning the raw
+/// payload into a [`Body`] according to the packet's TYPE flags, surfacing a
+/// [`CodecError`] if a string packet isn't valid UTF-8 or a JSON packet
+/// doesn't parse.
+pub struct BodyStream<R> {
+    inner: CodecStream<R>,
+}
+
+impl<R> BodyStream<R> {
+    /// Create a new `BodyStream`, wrapping the given reader.
+    pub fn new(reader: R) -> BodyStream<R> {
+        BodyStream { inner: CodecStream::new(reader) }
+    }
+
+    /// Consume the `BodyStream` to retrieve ownership of the inner reader.
+    pub fn into_inner(self) -> R {
+        self.inner.into_inner()
+    }
+}
+
+impl<R: AsyncRead + Unpin> TryStream for BodyStream<R> {
+    type Ok = (Body, Metadata);
+    type Error = CodecError;
+
+    fn try_poll_next(self: Pin<&mut Self>, wk: &Waker) -> Poll<Option<Result<Self::Ok, Self::Error>>> {
+        match Pin::new(&mut self.get_mut().inner).try_poll_next(wk) {
+            Ready(Some(Ok((data, metadata)))) => {
+                Ready(Some(Body::decode(data, &metadata).map(|body| (body, metadata))))
+            }
+            Ready(Some(Err(e))) => Ready(Some(Err(e))),
+            Ready(None) => Ready(None),
+            Pending => Pending,
+        }
+    }
+}
+
+/// This sink consumes pairs of [`Body`] and `Metadata`, encoding each `Body`
+/// into bytes and setting the packet's TYPE flags to match.
+///
+/// A thin [`CodecSink`] adapter: the `Metadata` passed to `start_send` only
+/// needs to set the `STREAM`/`END` flags and the id, since the TYPE flags
+/// are overwritten from the `Body` variant.
+pub struct BodySink<W> {
+    inner: CodecSink<W, Box<[u8]>>,
+}
+
+impl<W> BodySink<W> {
+    /// Create a new `BodySink`, wrapping the given writer.
+    ///
+    /// Every item is written out as soon as it's sent; see
+    /// [`BodySink::with_capacity`] for a throughput-oriented alternative.
+    pub fn new(writer: W) -> BodySink<W> {
+        BodySink { inner: CodecSink::new(writer) }
+    }
+
+    /// Create a `BodySink` that buffers encoded packets and only drains them
+    /// to `writer` once `high_water_mark` bytes have accumulated, or on an
+    /// explicit flush/close. See [`CodecSink::with_capacity`].
+    pub fn with_capacity(writer: W, high_water_mark: usize) -> BodySink<W> {
+        BodySink { inner: CodecSink::with_capacity(writer, high_water_mark) }
+    }
+
+    /// Consume the `BodySink` to retrieve ownership of the inner writer.
+    pub fn into_inner(self) -> W {
+        self.inner.into_inner()
+    }
+}
+
+impl<W: AsyncWrite + Unpin> Sink for BodySink<W> {
+    type SinkItem = (Body, Metadata);
+    type SinkError = CodecError;
+
+    fn poll_ready(self: Pin<&mut Self>, wk: &Waker) -> Poll<Result<(), Self::SinkError>> {
+        Pin::new(&mut self.get_mut().inner).poll_ready(wk)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Self::SinkItem) -> Result<(), Self::SinkError> {
+        let this = self.get_mut();
+        let (body, mut metadata) = item;
+        metadata.flags = (metadata.flags & !TYPE) | body.type_flag();
+        let data = body.into_bytes()?;
+        Pin::new(&mut this.inner).start_send((data, metadata))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, wk: &Waker) -> Poll<Result<(), Self::SinkError>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(wk)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, wk: &Waker) -> Poll<Result<(), Self::SinkError>> {
+        Pin::new(&mut self.get_mut().inner).poll_close(wk)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use async_ringbuffer::ring_buffer;
+    use futures_executor::block_on;
+    use futures_util::{SinkExt, TryStreamExt};
+
+    use crate::STREAM;
+
+    #[test]
+    fn decodes_binary_by_default() {
+        let metadata = Metadata { flags: 0, id: 1 };
+        let body = Body::decode(Box::new([1, 2, 3]), &metadata).unwrap();
+        assert_eq!(body, Body::Binary(Box::new([1, 2, 3])));
+    }
+
+    #[test]
+    fn decodes_a_string_packet() {
+        let metadata = Metadata { flags: TYPE_STRING, id: 1 };
+        let body = Body::decode(b"hi".to_vec().into_boxed_slice(), &metadata).unwrap();
+        assert_eq!(body, Body::String("hi".to_string()));
+    }
+
+    #[test]
+    fn rejects_invalid_utf8_in_a_string_packet() {
+        let metadata = Metadata { flags: TYPE_STRING, id: 1 };
+        match Body::decode(vec![0xff, 0xfe].into_boxed_slice(), &metadata) {
+            Err(CodecError::InvalidUtf8(_)) => {}
+            other => panic!("expected InvalidUtf8, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn decodes_a_json_packet() {
+        let metadata = Metadata { flags: TYPE_JSON, id: 1 };
+        let body = Body::decode(br#"{"a":1}"#.to_vec().into_boxed_slice(), &metadata).unwrap();
+        assert_eq!(body, Body::Json(serde_json::json!({"a": 1})));
+    }
+
+    #[test]
+    fn rejects_invalid_json_in_a_json_packet() {
+        let metadata = Metadata { flags: TYPE_JSON, id: 1 };
+        match Body::decode(b"not json".to_vec().into_boxed_slice(), &metadata) {
+            Err(CodecError::InvalidJson(_)) => {}
+            other => panic!("expected InvalidJson, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn encoding_a_body_sets_the_matching_type_flag_and_round_trips() {
+        let (writer, reader) = ring_buffer(64);
+
+        let mut sink = BodySink::new(writer);
+        let mut stream = BodyStream::new(reader);
+
+        block_on(async {
+            let item = (Body::Json(serde_json::json!({"ok": true})), Metadata { flags: STREAM, id: 3 });
+            await!(sink.send(item)).unwrap();
+            await!(sink.close()).unwrap();
+        });
+
+        let (body, metadata) = block_on(async { await!(stream.try_next()) }).unwrap().unwrap();
+        assert_eq!(body, Body::Json(serde_json::json!({"ok": true})));
+        assert!(metadata.is_json_packet());
+        assert!(metadata.is_stream_packet());
+        assert!(!metadata.is_end_packet());
+    }
+}
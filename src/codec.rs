@@ -0,0 +1,233 @@
+//! The [`Decoder`]/[`Encoder`] traits and the packet-stream wire format.
+
+use std::io;
+
+use bytes::{Buf, BufMut, BytesMut};
+use byteorder::{BigEndian, ByteOrder};
+
+use crate::error::{CodecError, Field};
+use crate::{Metadata, TYPE, TYPE_UNUSED};
+
+/// Number of bytes in a packet header: one flags byte, a 4-byte big-endian
+/// length, and a 4-byte big-endian id.
+const HEADER_LEN: usize = 9;
+
+/// Decodes a stream of items out of a growable byte buffer.
+///
+/// Implementations should only peek at `buf` until a whole item has been
+/// recognized, and advance it (e.g. via `BytesMut::split_to`/`advance`) only
+/// once that item is complete. Returning `Ok(None)` means `buf` does not yet
+/// hold a whole item and more bytes need to be read.
+pub trait Decoder {
+    /// The type of successfully decoded items.
+    type Item;
+    /// The error returned on a malformed item or a transport fault.
+    type Error: From<io::Error>;
+
+    /// Attempts to decode a single item from the front of `buf`.
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error>;
+
+    /// Called once the underlying transport has reported EOF, with whatever
+    /// `decode` was unable to turn into an item still sitting in `buf`.
+    ///
+    /// The default assumes any leftover bytes mean a frame was cut short.
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        match self.decode(buf)? {
+            Some(item) => Ok(Some(item)),
+            None if buf.is_empty() => Ok(None),
+            None => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "bytes remaining on stream").into()),
+        }
+    }
+}
+
+/// Encodes items of type `Item` into a growable byte buffer for writing to a
+/// transport.
+pub trait Encoder<Item> {
+    /// The error returned when `item` cannot be encoded.
+    type Error: From<io::Error>;
+
+    /// Appends the wire representation of `item` to `buf`.
+    fn encode(&mut self, item: Item, buf: &mut BytesMut) -> Result<(), Self::Error>;
+}
+
+/// The [`Decoder`]/[`Encoder`] for packet-stream's 9-byte-header framing.
+///
+/// This holds no state of its own, it just turns bytes into
+/// `(Box<[u8]>, Metadata)` pairs and back. [`CodecStream`](crate::CodecStream)
+/// and [`CodecSink`](crate::CodecSink) pair it with a
+/// [`FramedRead`](crate::FramedRead)/[`FramedWrite`](crate::FramedWrite) to
+/// drive it over an actual `AsyncRead`/`AsyncWrite`.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct PacketCodec {
+    _private: (),
+}
+
+impl PacketCodec {
+    /// Create a new `PacketCodec`.
+    pub fn new() -> PacketCodec {
+        PacketCodec { _private: () }
+    }
+}
+
+impl Decoder for PacketCodec {
+    type Item = (Box<[u8]>, Metadata);
+    type Error = CodecError;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, CodecError> {
+        if buf.is_empty() {
+            return Ok(None);
+        }
+
+        let flags = buf[0];
+        if flags & TYPE == TYPE_UNUSED {
+            return Err(CodecError::InvalidTypeFlag(flags));
+        }
+
+        if buf.len() < HEADER_LEN {
+            return Ok(None);
+        }
+
+        let length = BigEndian::read_u32(&buf[1..5]);
+        let id = BigEndian::read_i32(&buf[5..9]);
+
+        if length == 0 && flags == 0 && id == 0 {
+            buf.advance(HEADER_LEN);
+            return Ok(Some((Box::new([]), Metadata { flags, id })));
+        }
+
+        if buf.len() < HEADER_LEN + length as usize {
+            return Ok(None);
+        }
+
+        buf.advance(HEADER_LEN);
+        let data = buf.split_to(length as usize).to_vec().into_boxed_slice();
+
+        Ok(Some((data, Metadata { flags, id })))
+    }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, CodecError> {
+        if let Some(item) = self.decode(buf)? {
+            return Ok(Some(item));
+        }
+
+        let while_reading = match buf.len() {
+            0 => Field::Flags,
+            1..=4 => Field::Length,
+            5..=8 => Field::Id,
+            _ => Field::Data,
+        };
+
+        Err(CodecError::UnexpectedEof { while_reading })
+    }
+}
+
+impl<B: AsRef<[u8]>> Encoder<(B, Metadata)> for PacketCodec {
+    type Error = CodecError;
+
+    fn encode(&mut self, item: (B, Metadata), buf: &mut BytesMut) -> Result<(), CodecError> {
+        let (bytes, metadata) = item;
+        let bytes = bytes.as_ref();
+
+        if bytes.len() as u64 > u32::max_value() as u64 {
+            return Err(CodecError::ItemTooLarge(bytes.len()));
+        }
+
+        buf.reserve(HEADER_LEN + bytes.len());
+        buf.put_u8(metadata.flags);
+        buf.put_u32_be(bytes.len() as u32);
+        buf.put_i32_be(metadata.id);
+        buf.extend_from_slice(bytes);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_a_complete_packet() {
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(&[0, 0, 0, 0, 3, 0, 0, 0, 7, 1, 2, 3]);
+
+        let (data, metadata) = PacketCodec::new().decode(&mut buf).unwrap().unwrap();
+        assert_eq!(&*data, &[1, 2, 3][..]);
+        assert_eq!(metadata.flags, 0);
+        assert_eq!(metadata.id, 7);
+        assert!(buf.is_empty(), "the consumed bytes should be dropped from the buffer");
+    }
+
+    #[test]
+    fn decode_waits_for_a_full_header_before_consuming_anything() {
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(&[1, 0, 0]);
+
+        assert!(PacketCodec::new().decode(&mut buf).unwrap().is_none());
+        assert_eq!(buf.len(), 3, "partial input must be left untouched for the next read");
+    }
+
+    #[test]
+    fn decode_waits_for_the_full_payload() {
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(&[0, 0, 0, 0, 3, 0, 0, 0, 7, 1, 2]);
+
+        assert!(PacketCodec::new().decode(&mut buf).unwrap().is_none());
+        assert_eq!(buf.len(), 11);
+    }
+
+    #[test]
+    fn decode_recognizes_the_all_zero_end_of_stream_sentinel() {
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(&[0u8; HEADER_LEN]);
+
+        let (data, metadata) = PacketCodec::new().decode(&mut buf).unwrap().unwrap();
+        assert!(data.is_empty());
+        assert_eq!(metadata.flags, 0);
+        assert_eq!(metadata.id, 0);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn decode_rejects_the_reserved_type_flag() {
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(&[TYPE_UNUSED, 0, 0, 0, 0, 0, 0, 0, 0]);
+
+        match PacketCodec::new().decode(&mut buf) {
+            Err(CodecError::InvalidTypeFlag(flags)) => assert_eq!(flags, TYPE_UNUSED),
+            other => panic!("expected InvalidTypeFlag, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn decode_eof_reports_the_field_that_was_cut_short() {
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(&[0, 0, 0]);
+
+        match PacketCodec::new().decode_eof(&mut buf) {
+            Err(CodecError::UnexpectedEof { while_reading: Field::Length }) => {}
+            other => panic!("expected UnexpectedEof{{while_reading: Length}}, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn decode_eof_passes_through_a_complete_trailing_item() {
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(&[0, 0, 0, 0, 1, 0, 0, 0, 1, 42]);
+
+        let (data, _) = PacketCodec::new().decode_eof(&mut buf).unwrap().unwrap();
+        assert_eq!(&*data, &[42][..]);
+    }
+
+    #[test]
+    fn encode_writes_the_header_and_payload() {
+        let mut buf = BytesMut::new();
+        PacketCodec::new()
+            .encode((vec![1, 2, 3], Metadata { flags: 5, id: -9 }), &mut buf)
+            .unwrap();
+
+        let mut expected = vec![5, 0, 0, 0, 3, 255, 255, 255, 247];
+        expected.extend_from_slice(&[1, 2, 3]);
+        assert_eq!(&buf[..], &expected[..]);
+    }
+}
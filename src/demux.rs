@@ -0,0 +1,354 @@
+//! A muxrpc-style demultiplexer: splits one [`CodecStream`]/writer pair into
+//! many per-id duplex substreams.
+//!
+//! muxrpc multiplexes request/response calls and duplex streams over a
+//! single connection, routing packets by [`PacketId`]: a positive id names a
+//! stream the peer opened (or, from the opener's point of view, the id it
+//! picked for a stream it is opening), while the response/continuation side
+//! of that conversation reuses the negated id. [`Demux::new`] splits a
+//! [`CodecStream`]/writer pair into a driver that performs this routing, a
+//! [`DemuxHandle`] for opening outgoing streams, and an [`Incoming`] stream
+//! of substreams the peer opened.
+//!
+//! Scope: an END packet always closes its substream's route once delivered,
+//! but this module does not special-case an END packet whose body is a JSON
+//! error -- it's forwarded as an ordinary item, not surfaced as a distinct
+//! error. `Substream` is a plain `Stream<Item = Packet>` over raw bytes, with
+//! no error channel and no JSON dependency of its own (that parsing lives
+//! behind the optional `serde` feature in [`crate::body`]); giving it one
+//! here would mean either pulling JSON parsing into the zero-cost default
+//! path or making `Demux` generic over the typed [`crate::body::Body`]
+//! layer, both bigger changes than this module takes on. A caller that needs
+//! to distinguish an application-level error from a normal close can inspect
+//! the last packet's body itself.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Poll, Poll::Pending, Poll::Ready, Waker};
+
+use futures_channel::mpsc;
+use futures_core::stream::{Stream, TryStream};
+use futures_io::{AsyncRead, AsyncWrite};
+use futures_sink::Sink;
+
+use crate::error::CodecError;
+use crate::{CodecSink, CodecStream, Metadata, PacketId, END, STREAM};
+
+type Packet = (Box<[u8]>, Metadata);
+
+// State shared between the `Demux` driver, `DemuxHandle`s, and `Substream`s:
+// the id counter for streams opened locally, the routing table, and the
+// writer all outgoing packets are funneled through.
+struct Shared<W, B> {
+    sink: Mutex<CodecSink<W, B>>,
+    next_id: AtomicI32,
+    routes: Mutex<HashMap<PacketId, mpsc::UnboundedSender<Packet>>>,
+}
+
+/// Drives the demultiplexer: reads packets off the wrapped [`CodecStream`]
+/// and routes each one to the [`Substream`] (or the [`Incoming`] queue) it
+/// belongs to.
+///
+/// This does no work unless polled, so it must be spawned onto an executor
+/// (or otherwise driven to completion) for any `Substream` or `Incoming` to
+/// make progress. It resolves once the underlying `CodecStream` ends or
+/// errors.
+pub struct Demux<R, W, B> {
+    stream: CodecStream<R>,
+    shared: Arc<Shared<W, B>>,
+    incoming: mpsc::UnboundedSender<Substream<W, B>>,
+}
+
+/// A handle for opening new outgoing muxrpc streams.
+///
+/// Cheaply `Clone`able; every clone shares the same underlying writer and id
+/// counter, so streams opened from different clones never collide.
+pub struct DemuxHandle<W, B> {
+    shared: Arc<Shared<W, B>>,
+}
+
+impl<W, B> Clone for DemuxHandle<W, B> {
+    fn clone(&self) -> Self {
+        DemuxHandle { shared: self.shared.clone() }
+    }
+}
+
+/// A stream of [`Substream`]s that the peer opened.
+pub struct Incoming<W, B> {
+    new_streams: mpsc::UnboundedReceiver<Substream<W, B>>,
+}
+
+/// One multiplexed muxrpc conversation: a [`Stream`] of the packet bodies
+/// received for it, and a [`Sink`] for sending packet bodies on it, both
+/// sharing the connection's underlying writer.
+pub struct Substream<W, B> {
+    send_id: PacketId,
+    recv_id: PacketId,
+    packets: mpsc::UnboundedReceiver<Packet>,
+    shared: Arc<Shared<W, B>>,
+    // Whether the closing `STREAM | END` packet has already been enqueued,
+    // so a `poll_close` that's re-polled after a `Pending` flush doesn't
+    // start_send another one.
+    end_sent: bool,
+}
+
+impl<R, W, B> Demux<R, W, B> {
+    /// Split a `CodecStream`/writer pair into a driver, a handle for opening
+    /// outgoing streams, and a stream of incoming ones.
+    ///
+    /// `writer` is wrapped in a buffered [`CodecSink`] (as if by
+    /// [`CodecSink::with_capacity`] with a high-water mark of `0`) rather
+    /// than handed to an eagerly-flushing one: every `Substream` sends
+    /// through the same `Shared::sink`, under a lock that's only held for
+    /// the duration of a single `Sink` method call, so each item must be
+    /// fully encoded (or fully flushed) within one such call. The eager
+    /// mode's multi-call `SendState` machine assumes a single caller drives
+    /// it start-to-finish and would let one substream's in-flight write be
+    /// clobbered by another's, silently dropping packets; the buffered
+    /// path's `start_send` appends to a shared byte buffer in one atomic
+    /// step, which has no such assumption.
+    pub fn new(stream: CodecStream<R>, writer: W) -> (Demux<R, W, B>, DemuxHandle<W, B>, Incoming<W, B>) {
+        let shared = Arc::new(Shared {
+            sink: Mutex::new(CodecSink::with_capacity(writer, 0)),
+            next_id: AtomicI32::new(1),
+            routes: Mutex::new(HashMap::new()),
+        });
+
+        let (incoming_tx, incoming_rx) = mpsc::unbounded();
+
+        let demux = Demux {
+            stream,
+            shared: shared.clone(),
+            incoming: incoming_tx,
+        };
+
+        let handle = DemuxHandle { shared: shared.clone() };
+        let incoming = Incoming { new_streams: incoming_rx };
+
+        (demux, handle, incoming)
+    }
+}
+
+impl<R: AsyncRead + Unpin, W, B> Demux<R, W, B> {
+    // Routes one packet to its substream, opening a new one if the peer is
+    // the one initiating it. An END packet closes the route after delivery
+    // regardless of its body (see the module-level scoping note on JSON
+    // error bodies).
+    fn route(&mut self, data: Box<[u8]>, metadata: Metadata) {
+        let id = metadata.id;
+        let end = metadata.is_end_packet();
+
+        let mut routes = self.shared.routes.lock().unwrap();
+
+        if let Some(sender) = routes.get(&id) {
+            let _ = sender.unbounded_send((data, metadata));
+            if end {
+                routes.remove(&id);
+            }
+            return;
+        }
+
+        if id <= 0 {
+            // A response/continuation for a stream we don't (or no longer)
+            // know about, e.g. one we already dropped our end of. Nothing
+            // sensible to route it to.
+            return;
+        }
+
+        let (sender, receiver) = mpsc::unbounded();
+        let _ = sender.unbounded_send((data, metadata));
+        if !end {
+            routes.insert(id, sender);
+        }
+        drop(routes);
+
+        let substream = Substream {
+            send_id: -id,
+            recv_id: id,
+            packets: receiver,
+            shared: self.shared.clone(),
+            end_sent: false,
+        };
+
+        let _ = self.incoming.unbounded_send(substream);
+    }
+}
+
+impl<R: AsyncRead + Unpin, W, B> Future for Demux<R, W, B> {
+    type Output = Result<(), CodecError>;
+
+    fn poll(self: Pin<&mut Self>, wk: &Waker) -> Poll<Self::Output> {
+        let this = self.get_mut();
+
+        loop {
+            match Pin::new(&mut this.stream).try_poll_next(wk) {
+                Pending => return Pending,
+                Ready(None) => return Ready(Ok(())),
+                Ready(Some(Err(e))) => return Ready(Err(e)),
+                Ready(Some(Ok((data, metadata)))) => this.route(data, metadata),
+            }
+        }
+    }
+}
+
+impl<W, B> DemuxHandle<W, B> {
+    /// Open a fresh outgoing stream, allocating the next id.
+    pub fn open_stream(&self) -> Substream<W, B> {
+        let id = self.shared.next_id.fetch_add(1, Ordering::Relaxed);
+
+        let (sender, receiver) = mpsc::unbounded();
+        self.shared.routes.lock().unwrap().insert(-id, sender);
+
+        Substream {
+            send_id: id,
+            recv_id: -id,
+            packets: receiver,
+            shared: self.shared.clone(),
+            end_sent: false,
+        }
+    }
+}
+
+impl<W, B> Stream for Incoming<W, B> {
+    type Item = Substream<W, B>;
+
+    fn poll_next(self: Pin<&mut Self>, wk: &Waker) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.get_mut().new_streams).poll_next(wk)
+    }
+}
+
+impl<W, B> Stream for Substream<W, B> {
+    type Item = Packet;
+
+    fn poll_next(self: Pin<&mut Self>, wk: &Waker) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.get_mut().packets).poll_next(wk)
+    }
+}
+
+impl<W, B> Drop for Substream<W, B> {
+    fn drop(&mut self) {
+        self.shared.routes.lock().unwrap().remove(&self.recv_id);
+    }
+}
+
+impl<W, B> Sink for Substream<W, B>
+    where W: AsyncWrite + Unpin,
+          B: AsRef<[u8]> + Unpin + Default
+{
+    type SinkItem = B;
+    type SinkError = CodecError;
+
+    fn poll_ready(self: Pin<&mut Self>, wk: &Waker) -> Poll<Result<(), Self::SinkError>> {
+        Pin::new(&mut *self.get_mut().shared.sink.lock().unwrap()).poll_ready(wk)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Self::SinkItem) -> Result<(), Self::SinkError> {
+        let this = self.get_mut();
+        let metadata = Metadata { flags: STREAM, id: this.send_id };
+        Pin::new(&mut *this.shared.sink.lock().unwrap()).start_send((item, metadata))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, wk: &Waker) -> Poll<Result<(), Self::SinkError>> {
+        Pin::new(&mut *self.get_mut().shared.sink.lock().unwrap()).poll_flush(wk)
+    }
+
+    // Sends a final `STREAM | END` packet closing this substream's side of
+    // the conversation. Does *not* close the shared writer: other
+    // substreams (and the connection itself) stay open.
+    //
+    // `end_sent` guards the `start_send`: if `poll_flush` below returns
+    // `Pending` under writer backpressure, `close()` re-polls `poll_close`,
+    // and without the guard that would enqueue a second `STREAM | END`
+    // packet rather than just resuming the flush already in progress.
+    fn poll_close(self: Pin<&mut Self>, wk: &Waker) -> Poll<Result<(), Self::SinkError>> {
+        let this = self.get_mut();
+        let mut sink = this.shared.sink.lock().unwrap();
+
+        if !this.end_sent {
+            let metadata = Metadata { flags: STREAM | END, id: this.send_id };
+            Pin::new(&mut *sink).start_send((B::default(), metadata))?;
+            this.end_sent = true;
+        }
+
+        Pin::new(&mut *sink).poll_flush(wk)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures_executor::block_on;
+    use futures_util::StreamExt;
+
+    // `Demux::route` is exercised directly below, without ever polling the
+    // `Demux` future, so this reader is never actually read from.
+    struct NeverRead;
+
+    impl AsyncRead for NeverRead {
+        fn poll_read(&mut self, _wk: &Waker, _buf: &mut [u8]) -> Poll<Result<usize, futures_io::Error>> {
+            Pending
+        }
+    }
+
+    #[test]
+    fn routes_a_peer_opened_packet_to_a_new_incoming_substream() {
+        let stream = CodecStream::new(NeverRead);
+        let (mut demux, _handle, mut incoming): (_, DemuxHandle<(), Box<[u8]>>, _) =
+            Demux::new(stream, ());
+
+        demux.route(vec![1, 2, 3].into_boxed_slice(), Metadata { flags: STREAM, id: 5 });
+
+        let mut substream = block_on(async { await!(incoming.next()) }).unwrap();
+        assert_eq!(substream.recv_id, 5);
+        assert_eq!(substream.send_id, -5, "the send side of a peer-opened stream reuses the negated id");
+
+        let (data, metadata) = block_on(async { await!(substream.next()) }).unwrap();
+        assert_eq!(&*data, &[1, 2, 3][..]);
+        assert_eq!(metadata.id, 5);
+    }
+
+    #[test]
+    fn a_response_side_packet_with_no_matching_route_is_silently_dropped() {
+        let stream = CodecStream::new(NeverRead);
+        let (mut demux, _handle, _incoming): (_, DemuxHandle<(), Box<[u8]>>, Incoming<(), Box<[u8]>>) =
+            Demux::new(stream, ());
+
+        // Id <= 0 with no registered route: nothing is listening for this
+        // response/continuation, so `route` has nothing sensible to do with
+        // it, and must not panic or fabricate a substream for it.
+        demux.route(vec![1].into_boxed_slice(), Metadata { flags: 0, id: -42 });
+
+        assert!(demux.shared.routes.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn an_end_packet_does_not_register_a_route_for_a_single_packet_stream() {
+        let stream = CodecStream::new(NeverRead);
+        let (mut demux, _handle, mut incoming): (_, DemuxHandle<(), Box<[u8]>>, _) =
+            Demux::new(stream, ());
+
+        demux.route(vec![].into_boxed_slice(), Metadata { flags: STREAM | END, id: 9 });
+
+        let substream = block_on(async { await!(incoming.next()) }).unwrap();
+        assert_eq!(substream.recv_id, 9);
+        assert!(demux.shared.routes.lock().unwrap().get(&9).is_none());
+    }
+
+    #[test]
+    fn locally_opened_streams_get_sequential_ids_with_a_negated_send_side() {
+        let stream = CodecStream::new(NeverRead);
+        let (_demux, handle, _incoming): (_, _, Incoming<(), Box<[u8]>>) = Demux::new(stream, ());
+
+        let first = handle.open_stream();
+        assert_eq!(first.send_id, 1);
+        assert_eq!(first.recv_id, -1);
+
+        let second = handle.open_stream();
+        assert_eq!(second.send_id, 2);
+        assert_eq!(second.recv_id, -2);
+    }
+}
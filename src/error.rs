@@ -0,0 +1,114 @@
+//! The [`CodecError`] type returned by this crate's codecs, sinks, and streams.
+
+use std::error;
+use std::fmt;
+use std::io;
+
+/// Which field of a packet header/body was being read when the underlying
+/// transport reported EOF.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Field {
+    /// The 1-byte flags field.
+    Flags,
+    /// The 4-byte big-endian length field.
+    Length,
+    /// The 4-byte big-endian id field.
+    Id,
+    /// The packet's data payload.
+    Data,
+}
+
+impl fmt::Display for Field {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Field::Flags => "flags",
+            Field::Length => "length",
+            Field::Id => "id",
+            Field::Data => "data",
+        })
+    }
+}
+
+/// The error type produced by this crate's [`Decoder`](crate::Decoder)/
+/// [`Encoder`](crate::Encoder) implementations, and by the
+/// [`CodecStream`](crate::CodecStream)/[`CodecSink`](crate::CodecSink) built
+/// on top of them.
+///
+/// This separates protocol faults (a malformed frame) from transport faults
+/// (an I/O error reported by the underlying reader/writer), so callers can
+/// tell a bad peer from a dropped connection without string-matching an
+/// `io::ErrorKind`.
+#[derive(Debug)]
+pub enum CodecError {
+    /// The underlying reader or writer returned an error.
+    Io(io::Error),
+    /// A packet's type flags had the reserved, unused value.
+    InvalidTypeFlag(u8),
+    /// An item passed to an encoder was larger than `u32::max_value()` bytes.
+    ItemTooLarge(usize),
+    /// The transport reported EOF while a packet was only partially read.
+    UnexpectedEof {
+        /// The field that was being read when the transport closed.
+        while_reading: Field,
+    },
+    /// A packet with the string type flag did not contain valid UTF-8.
+    #[cfg(feature = "serde")]
+    InvalidUtf8(std::string::FromUtf8Error),
+    /// A packet with the JSON type flag did not contain valid JSON.
+    #[cfg(feature = "serde")]
+    InvalidJson(serde_json::Error),
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CodecError::Io(e) => write!(f, "i/o error: {}", e),
+            CodecError::InvalidTypeFlag(flags) => {
+                write!(f, "read packet with invalid type flag: {:#04x}", flags)
+            }
+            CodecError::ItemTooLarge(len) => {
+                write!(f, "item of {} bytes is too large for packet-stream-codec", len)
+            }
+            CodecError::UnexpectedEof { while_reading } => {
+                write!(f, "transport closed while reading packet {}", while_reading)
+            }
+            #[cfg(feature = "serde")]
+            CodecError::InvalidUtf8(e) => write!(f, "packet with string type flag was not valid utf-8: {}", e),
+            #[cfg(feature = "serde")]
+            CodecError::InvalidJson(e) => write!(f, "packet with json type flag was not valid json: {}", e),
+        }
+    }
+}
+
+impl error::Error for CodecError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            CodecError::Io(e) => Some(e),
+            #[cfg(feature = "serde")]
+            CodecError::InvalidUtf8(e) => Some(e),
+            #[cfg(feature = "serde")]
+            CodecError::InvalidJson(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl From<std::string::FromUtf8Error> for CodecError {
+    fn from(e: std::string::FromUtf8Error) -> CodecError {
+        CodecError::InvalidUtf8(e)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl From<serde_json::Error> for CodecError {
+    fn from(e: serde_json::Error) -> CodecError {
+        CodecError::InvalidJson(e)
+    }
+}
+
+impl From<io::Error> for CodecError {
+    fn from(e: io::Error) -> CodecError {
+        CodecError::Io(e)
+    }
+}
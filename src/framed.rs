@@ -0,0 +1,312 @@
+//! Generic `AsyncRead`/`AsyncWrite` wrappers driven by a [`Decoder`]/[`Encoder`].
+
+use std::pin::Pin;
+use std::task::{Poll, Poll::Pending, Poll::Ready, Waker};
+
+use bytes::BytesMut;
+use futures_core::stream::TryStream;
+use futures_io::{AsyncRead, AsyncWrite, Error};
+use futures_io::ErrorKind::{Interrupted, WriteZero};
+use futures_sink::Sink;
+use futures_util::try_ready;
+
+use crate::codec::{Decoder, Encoder};
+
+/// Amount by which the read buffer grows each time more bytes are needed.
+const INITIAL_CAPACITY: usize = 8 * 1024;
+
+/// Decodes a stream of `D::Item`s out of an `AsyncRead`, using `D` to turn
+/// buffered bytes into items.
+///
+/// This is the generic machinery behind [`CodecStream`](crate::CodecStream):
+/// it owns the reader and a growable buffer, and repeatedly asks the codec
+/// whether the buffer holds a complete item yet before reading more.
+pub struct FramedRead<R, D> {
+    reader: R,
+    codec: D,
+    buffer: BytesMut,
+}
+
+impl<R, D> FramedRead<R, D> {
+    /// Wrap `reader`, decoding items with `codec`.
+    pub fn new(reader: R, codec: D) -> FramedRead<R, D> {
+        FramedRead {
+            reader,
+            codec,
+            buffer: BytesMut::new(),
+        }
+    }
+
+    /// Consume the `FramedRead`, returning ownership of the wrapped reader.
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+impl<R: AsyncRead + Unpin, D> FramedRead<R, D> {
+    // Reads more bytes from `reader` into `buffer`, growing it as needed.
+    //
+    // Grows `buffer` with a zero-initialized tail rather than exposing its
+    // uninitialized spare capacity, and retries on `Interrupted` instead of
+    // treating it as a fatal error, since it's a transient condition (e.g. a
+    // read interrupted by a signal) that just means "try again".
+    fn fill_read_buf(&mut self, wk: &Waker) -> Poll<Result<usize, Error>> {
+        let filled = self.buffer.len();
+        self.buffer.resize(filled + INITIAL_CAPACITY, 0);
+
+        loop {
+            match self.reader.poll_read(wk, &mut self.buffer[filled..]) {
+                Pending => {
+                    self.buffer.truncate(filled);
+                    return Pending;
+                }
+                Ready(Err(ref e)) if e.kind() == Interrupted => continue,
+                Ready(Err(e)) => {
+                    self.buffer.truncate(filled);
+                    return Ready(Err(e));
+                }
+                Ready(Ok(n)) => {
+                    self.buffer.truncate(filled + n);
+                    return Ready(Ok(n));
+                }
+            }
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin, D: Decoder> TryStream for FramedRead<R, D> {
+    type Ok = D::Item;
+    type Error = D::Error;
+
+    fn try_poll_next(mut self: Pin<&mut Self>, wk: &Waker) -> Poll<Option<Result<Self::Ok, Self::Error>>> {
+        loop {
+            match self.codec.decode(&mut self.buffer) {
+                Err(e) => return Ready(Some(Err(e))),
+                Ok(Some(item)) => return Ready(Some(Ok(item))),
+                Ok(None) => {}
+            }
+
+            let n = match self.fill_read_buf(wk) {
+                Pending => return Pending,
+                Ready(Err(e)) => return Ready(Some(Err(e.into()))),
+                Ready(Ok(n)) => n,
+            };
+
+            if n == 0 {
+                return Ready(match self.codec.decode_eof(&mut self.buffer) {
+                    Ok(Some(item)) => Some(Ok(item)),
+                    Ok(None) => None,
+                    Err(e) => Some(Err(e)),
+                });
+            }
+        }
+    }
+}
+
+// State for draining the output buffer to the writer.
+enum WriteState {
+    Idle,
+    Flushing(usize), // how many bytes of `buffer` have already been written
+}
+
+/// Encodes and writes a sequence of items to an `AsyncWrite`, using `C` to
+/// turn each item into bytes.
+///
+/// This is the generic machinery behind [`CodecSink`](crate::CodecSink): it
+/// owns the writer and an output buffer, `start_send` appends to the buffer,
+/// and `poll_flush`/`poll_close` drain it to the writer.
+pub struct FramedWrite<W, C> {
+    writer: W,
+    codec: C,
+    buffer: BytesMut,
+    state: WriteState,
+}
+
+impl<W, C> FramedWrite<W, C> {
+    /// Wrap `writer`, encoding items with `codec`.
+    pub fn new(writer: W, codec: C) -> FramedWrite<W, C> {
+        FramedWrite {
+            writer,
+            codec,
+            buffer: BytesMut::new(),
+            state: WriteState::Idle,
+        }
+    }
+
+    /// Consume the `FramedWrite`, returning ownership of the wrapped writer.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    // Gives protocol-specific adapters (e.g. `CodecSink`) a way to splice
+    // raw bytes (like an end-of-stream marker) into the output buffer
+    // without going through the `Encoder` trait.
+    pub(crate) fn buffer_mut(&mut self) -> &mut BytesMut {
+        &mut self.buffer
+    }
+
+    // Gives protocol-specific adapters a way to write directly to the
+    // wrapped writer (e.g. with `poll_write_vectored`), bypassing the
+    // output buffer entirely for a zero-copy fast path.
+    pub(crate) fn writer_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+}
+
+impl<W: AsyncWrite + Unpin, C> FramedWrite<W, C> {
+    fn do_poll_flush(&mut self, wk: &Waker) -> Poll<Result<(), Error>> {
+        let mut offset = match self.state {
+            WriteState::Idle => 0,
+            WriteState::Flushing(offset) => offset,
+        };
+
+        while offset < self.buffer.len() {
+            let written = try_ready!(self.writer.poll_write(wk, &self.buffer[offset..]));
+
+            if written == 0 {
+                return Ready(Err(Error::new(WriteZero, "failed to write packet-stream data")));
+            }
+
+            offset += written;
+            self.state = WriteState::Flushing(offset);
+        }
+
+        self.buffer.clear();
+        self.state = WriteState::Idle;
+        self.writer.poll_flush(wk)
+    }
+}
+
+impl<W: AsyncWrite + Unpin, C, Item> Sink for FramedWrite<W, C>
+    where C: Encoder<Item>
+{
+    type SinkItem = Item;
+    type SinkError = C::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, _wk: &Waker) -> Poll<Result<(), Self::SinkError>> {
+        Ready(Ok(()))
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Self::SinkItem) -> Result<(), Self::SinkError> {
+        self.codec.encode(item, &mut self.buffer)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, wk: &Waker) -> Poll<Result<(), Self::SinkError>> {
+        match self.do_poll_flush(wk) {
+            Pending => Pending,
+            Ready(Ok(())) => Ready(Ok(())),
+            Ready(Err(e)) => Ready(Err(e.into())),
+        }
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, wk: &Waker) -> Poll<Result<(), Self::SinkError>> {
+        match self.do_poll_flush(wk) {
+            Pending => return Pending,
+            Ready(Err(e)) => return Ready(Err(e.into())),
+            Ready(Ok(())) => {}
+        }
+
+        match self.writer.poll_close(wk) {
+            Pending => Pending,
+            Ready(Ok(())) => Ready(Ok(())),
+            Ready(Err(e)) => Ready(Err(e.into())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures_executor::block_on;
+    use futures_util::future::poll_fn;
+
+    use crate::codec::PacketCodec;
+    use crate::error::{CodecError, Field};
+    use crate::Metadata;
+
+    // A reader that fails with `Interrupted` a fixed number of times before
+    // successfully copying the rest of `data` into the caller's buffer.
+    struct FlakyReader {
+        data: Vec<u8>,
+        interrupts_remaining: usize,
+    }
+
+    impl AsyncRead for FlakyReader {
+        fn poll_read(&mut self, _wk: &Waker, buf: &mut [u8]) -> Poll<Result<usize, Error>> {
+            if self.interrupts_remaining > 0 {
+                self.interrupts_remaining -= 1;
+                return Ready(Err(Error::new(Interrupted, "try again")));
+            }
+
+            let n = self.data.len().min(buf.len());
+            buf[..n].copy_from_slice(&self.data[..n]);
+            self.data.drain(..n);
+            Ready(Ok(n))
+        }
+    }
+
+    #[test]
+    fn fill_read_buf_retries_after_being_interrupted() {
+        let mut packet = BytesMut::new();
+        PacketCodec::new()
+            .encode((vec![9, 8, 7], Metadata { flags: 0, id: 1 }), &mut packet)
+            .unwrap();
+
+        let reader = FlakyReader { data: packet.to_vec(), interrupts_remaining: 2 };
+        let mut framed = FramedRead::new(reader, PacketCodec::new());
+
+        let item = block_on(async { await!(poll_fn(|wk| Pin::new(&mut framed).try_poll_next(wk))) });
+        let (data, metadata) = item.unwrap().unwrap();
+        assert_eq!(&*data, &[9, 8, 7][..]);
+        assert_eq!(metadata.id, 1);
+    }
+
+    // A reader that hands over `data` once, then reports EOF (a zero-byte
+    // read) on every subsequent call.
+    struct EofAfter {
+        data: Option<Vec<u8>>,
+    }
+
+    impl AsyncRead for EofAfter {
+        fn poll_read(&mut self, _wk: &Waker, buf: &mut [u8]) -> Poll<Result<usize, Error>> {
+            match self.data.take() {
+                Some(data) => {
+                    let n = data.len().min(buf.len());
+                    buf[..n].copy_from_slice(&data[..n]);
+                    Ready(Ok(n))
+                }
+                None => Ready(Ok(0)),
+            }
+        }
+    }
+
+    #[test]
+    fn eof_with_a_partial_frame_reports_which_field_was_cut_short() {
+        // Only flags and length: never enough to form a complete packet.
+        let reader = EofAfter { data: Some(vec![0, 0, 0, 0, 3]) };
+        let mut framed = FramedRead::new(reader, PacketCodec::new());
+
+        let item = block_on(async { await!(poll_fn(|wk| Pin::new(&mut framed).try_poll_next(wk))) });
+        match item {
+            Some(Err(CodecError::UnexpectedEof { while_reading: Field::Id })) => {}
+            other => panic!("expected UnexpectedEof{{while_reading: Id}}, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn eof_with_nothing_buffered_is_still_an_unexpected_eof() {
+        // This codec's clean-end signal is the explicit all-zero sentinel
+        // packet (see `CodecStream`), not a bare transport EOF, so a
+        // connection that closes without ever sending one is truncated
+        // regardless of whether anything is left in the buffer.
+        let reader = EofAfter { data: None };
+        let mut framed = FramedRead::new(reader, PacketCodec::new());
+
+        let item = block_on(async { await!(poll_fn(|wk| Pin::new(&mut framed).try_poll_next(wk))) });
+        match item {
+            Some(Err(CodecError::UnexpectedEof { while_reading: Field::Flags })) => {}
+            other => panic!("expected UnexpectedEof{{while_reading: Flags}}, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,330 @@
+//! The packet-stream `Sink` adapter.
+
+use std::io::{self, IoSlice};
+use std::pin::Pin;
+use std::task::Poll;
+use std::task::Poll::{Pending, Ready};
+use std::task::Waker;
+
+use byteorder::{BigEndian, ByteOrder};
+use futures_io::AsyncWrite;
+use futures_sink::Sink;
+
+use crate::codec::PacketCodec;
+use crate::error::CodecError;
+use crate::framed::FramedWrite;
+use crate::Metadata;
+
+// An all-zero header, sent once on `poll_close` to signal end-of-stream.
+const ZEROS: [u8; 9] = [0u8; 9];
+
+enum CloseState {
+    Open,
+    EndOfStreamWritten,
+}
+
+// The item currently being written out, tracked byte-by-byte so a partial
+// write can resume exactly where it left off.
+enum SendState<B> {
+    Idle,
+    Writing {
+        header: [u8; 9],
+        header_offset: u8,
+        body: B,
+        body_offset: u32,
+    },
+}
+
+fn encode_header(metadata: Metadata, len: u32) -> [u8; 9] {
+    let mut header = [0u8; 9];
+    header[0] = metadata.flags;
+    BigEndian::write_u32(&mut header[1..5], len);
+    BigEndian::write_i32(&mut header[5..9], metadata.id);
+    header
+}
+
+/// This sink consumes pairs of `Metadata` and `AsRef<[u8]>`s of type `B` and
+/// encodes them into the wrapped `AsyncWrite` of type `W`.
+///
+/// By default (via [`CodecSink::new`]) each item is flushed eagerly: its
+/// 9-byte header and payload are assembled once and submitted together via
+/// `poll_write_vectored`, falling back to a byte-offset state machine only
+/// when a write is split across the header/payload boundary. Use
+/// [`CodecSink::with_capacity`] instead to buffer many small packets into
+/// one larger write, trading latency for throughput. Either way, the
+/// protocol's all-zero end-of-stream marker is appended directly to the
+/// underlying [`FramedWrite`]'s output buffer on close, without going
+/// through [`PacketCodec`].
+pub struct CodecSink<W, B> {
+    inner: FramedWrite<W, PacketCodec>,
+    send_state: SendState<B>,
+    close_state: CloseState,
+    // `None` for the eager, per-item `send_state` path; `Some(mark)` to
+    // instead accumulate encoded packets in `inner`'s buffer and only drain
+    // them once it holds at least `mark` bytes (or on an explicit flush).
+    high_water_mark: Option<usize>,
+}
+
+impl<W, B> CodecSink<W, B> {
+    /// Create a new `CodecSink`, wrapping the given writer.
+    ///
+    /// Every item is written out as soon as it's sent; see
+    /// [`CodecSink::with_capacity`] for a throughput-oriented alternative.
+    pub fn new(writer: W) -> CodecSink<W, B> {
+        CodecSink {
+            inner: FramedWrite::new(writer, PacketCodec::new()),
+            send_state: SendState::Idle,
+            close_state: CloseState::Open,
+            high_water_mark: None,
+        }
+    }
+
+    /// Create a `CodecSink` that buffers encoded packets and only drains
+    /// them to `writer` once `high_water_mark` bytes have accumulated, or on
+    /// an explicit flush/close.
+    ///
+    /// This coalesces many small packets into fewer, larger writes, at the
+    /// cost of latency: a sent item may sit in the buffer for a while before
+    /// it actually reaches `writer`. Latency-sensitive callers should use
+    /// [`CodecSink::new`] instead.
+    pub fn with_capacity(writer: W, high_water_mark: usize) -> CodecSink<W, B> {
+        CodecSink {
+            inner: FramedWrite::new(writer, PacketCodec::new()),
+            send_state: SendState::Idle,
+            close_state: CloseState::Open,
+            high_water_mark: Some(high_water_mark),
+        }
+    }
+
+    /// Consume the `CodecSink` to retrieve ownership of the inner writer.
+    pub fn into_inner(self) -> W {
+        self.inner.into_inner()
+    }
+}
+
+impl<W, B> CodecSink<W, B>
+    where W: AsyncWrite + Unpin,
+          B: AsRef<[u8]> + Unpin
+{
+    // Drives `send_state` to `Idle`, writing out whatever of the pending
+    // item's header/body hasn't made it to the writer yet.
+    fn do_poll_flush(&mut self, wk: &Waker) -> Poll<Result<(), CodecError>> {
+        loop {
+            let (header, header_offset, body, body_offset) = match &mut self.send_state {
+                SendState::Idle => break,
+                SendState::Writing { header, header_offset, body, body_offset } => {
+                    (header, header_offset, body, body_offset)
+                }
+            };
+
+            let header_rest = &header[*header_offset as usize..];
+            let body_rest = &body.as_ref()[*body_offset as usize..];
+
+            let written = if header_rest.is_empty() {
+                match self.inner.writer_mut().poll_write(wk, body_rest) {
+                    Pending => return Pending,
+                    Ready(Err(e)) => return Ready(Err(e.into())),
+                    Ready(Ok(n)) => n,
+                }
+            } else {
+                let slices = [IoSlice::new(header_rest), IoSlice::new(body_rest)];
+                match self.inner.writer_mut().poll_write_vectored(wk, &slices) {
+                    Pending => return Pending,
+                    Ready(Err(e)) => return Ready(Err(e.into())),
+                    Ready(Ok(n)) => n,
+                }
+            };
+
+            if written == 0 {
+                return Ready(Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write packet-stream data").into()));
+            }
+
+            if !header_rest.is_empty() && written <= header_rest.len() {
+                *header_offset += written as u8;
+            } else {
+                let body_written = written - header_rest.len();
+                *header_offset = header.len() as u8;
+                *body_offset += body_written as u32;
+            }
+
+            if *header_offset as usize == header.len() && *body_offset as usize == body.as_ref().len() {
+                self.send_state = SendState::Idle;
+            }
+        }
+
+        match Pin::new(&mut self.inner).poll_flush(wk) {
+            Pending => Pending,
+            Ready(Ok(())) => Ready(Ok(())),
+            Ready(Err(e)) => Ready(Err(e)),
+        }
+    }
+}
+
+impl<W, B> Sink for CodecSink<W, B>
+    where W: AsyncWrite + Unpin,
+          B: AsRef<[u8]> + Unpin
+{
+    /// The length of the [u8] may not be larger than `u32::max_value()`.
+    /// Otherwise, `start_send` returns [`CodecError::ItemTooLarge`].
+    type SinkItem = (B, Metadata);
+    type SinkError = CodecError;
+
+    fn poll_ready(self: Pin<&mut Self>, wk: &Waker) -> Poll<Result<(), Self::SinkError>> {
+        let this = self.get_mut();
+
+        if let Some(high_water_mark) = this.high_water_mark {
+            return if this.inner.buffer_mut().len() >= high_water_mark {
+                this.do_poll_flush(wk)
+            } else {
+                Ready(Ok(()))
+            };
+        }
+
+        match this.send_state {
+            SendState::Idle => Ready(Ok(())),
+            SendState::Writing { .. } => this.do_poll_flush(wk),
+        }
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Self::SinkItem) -> Result<(), Self::SinkError> {
+        let this = self.get_mut();
+
+        if this.high_water_mark.is_some() {
+            return Pin::new(&mut this.inner).start_send(item);
+        }
+
+        let (body, metadata) = item;
+        let len = body.as_ref().len();
+
+        if len as u64 > u32::max_value() as u64 {
+            return Err(CodecError::ItemTooLarge(len));
+        }
+
+        this.send_state = SendState::Writing {
+            header: encode_header(metadata, len as u32),
+            header_offset: 0,
+            body,
+            body_offset: 0,
+        };
+
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, wk: &Waker) -> Poll<Result<(), Self::SinkError>> {
+        self.get_mut().do_poll_flush(wk)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, wk: &Waker) -> Poll<Result<(), Self::SinkError>> {
+        let this = self.get_mut();
+
+        match this.do_poll_flush(wk) {
+            Pending => return Pending,
+            Ready(Err(e)) => return Ready(Err(e)),
+            Ready(Ok(())) => {}
+        }
+
+        if let CloseState::Open = this.close_state {
+            this.inner.buffer_mut().extend_from_slice(&ZEROS);
+            this.close_state = CloseState::EndOfStreamWritten;
+        }
+
+        Pin::new(&mut this.inner).poll_close(wk)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures_executor::block_on;
+    use futures_util::future::poll_fn;
+    use futures_util::SinkExt;
+
+    // An `AsyncWrite` that only ever accepts up to `limit` bytes per call,
+    // so tests can force a write to split across the header/payload
+    // boundary instead of completing in one go.
+    struct ChunkedWriter {
+        data: Vec<u8>,
+        limit: usize,
+    }
+
+    impl AsyncWrite for ChunkedWriter {
+        fn poll_write(&mut self, _wk: &Waker, buf: &[u8]) -> Poll<Result<usize, io::Error>> {
+            let n = buf.len().min(self.limit);
+            self.data.extend_from_slice(&buf[..n]);
+            Ready(Ok(n))
+        }
+
+        fn poll_write_vectored(&mut self, _wk: &Waker, bufs: &[IoSlice]) -> Poll<Result<usize, io::Error>> {
+            let mut remaining = self.limit;
+            let mut written = 0;
+
+            for buf in bufs {
+                if remaining == 0 {
+                    break;
+                }
+                let n = buf.len().min(remaining);
+                self.data.extend_from_slice(&buf[..n]);
+                written += n;
+                remaining -= n;
+            }
+
+            Ready(Ok(written))
+        }
+
+        fn poll_flush(&mut self, _wk: &Waker) -> Poll<Result<(), io::Error>> {
+            Ready(Ok(()))
+        }
+
+        fn poll_close(&mut self, _wk: &Waker) -> Poll<Result<(), io::Error>> {
+            Ready(Ok(()))
+        }
+    }
+
+    fn encoded_packet(flags: u8, id: i32, body: &[u8]) -> Vec<u8> {
+        let mut packet = encode_header(Metadata { flags, id }, body.len() as u32).to_vec();
+        packet.extend_from_slice(body);
+        packet
+    }
+
+    #[test]
+    fn vectored_write_resumes_at_the_right_offset_after_a_partial_write() {
+        let writer = ChunkedWriter { data: Vec::new(), limit: 4 };
+        let mut sink: CodecSink<_, Vec<u8>> = CodecSink::new(writer);
+
+        block_on(async {
+            await!(sink.send((vec![1, 2, 3], Metadata { flags: 0, id: 7 }))).unwrap();
+        });
+
+        assert_eq!(sink.into_inner().data, encoded_packet(0, 7, &[1, 2, 3]));
+    }
+
+    #[test]
+    fn buffered_mode_start_send_only_accumulates_without_writing() {
+        let writer = ChunkedWriter { data: Vec::new(), limit: 1024 };
+        let mut sink: CodecSink<_, Vec<u8>> = CodecSink::with_capacity(writer, 9999);
+
+        // Neither `start_send` writes to `writer` at all: both packets just
+        // accumulate in the shared output buffer.
+        Pin::new(&mut sink).start_send((vec![1, 2, 3], Metadata { flags: 0, id: 1 })).unwrap();
+        Pin::new(&mut sink).start_send((vec![4, 5], Metadata { flags: 0, id: 2 })).unwrap();
+        assert!(sink.into_inner().data.is_empty());
+    }
+
+    #[test]
+    fn buffered_mode_flushes_automatically_once_the_high_water_mark_is_reached() {
+        let writer = ChunkedWriter { data: Vec::new(), limit: 1024 };
+        let mut sink: CodecSink<_, Vec<u8>> = CodecSink::with_capacity(writer, 1);
+
+        Pin::new(&mut sink).start_send((vec![1, 2, 3], Metadata { flags: 0, id: 1 })).unwrap();
+
+        // The buffer now holds more than the 1-byte high-water mark, so
+        // `poll_ready` (called here ahead of the second item) must flush it
+        // before accepting anything else.
+        block_on(async {
+            await!(poll_fn(|wk| Pin::new(&mut sink).poll_ready(wk))).unwrap();
+        });
+
+        assert_eq!(sink.into_inner().data, encoded_packet(0, 1, &[1, 2, 3]));
+    }
+}
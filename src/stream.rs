@@ -0,0 +1,55 @@
+//! The packet-stream `TryStream` adapter.
+
+use std::pin::Pin;
+use std::task::Poll::Ready;
+use std::task::{Poll, Waker};
+
+use futures_core::stream::TryStream;
+use futures_io::AsyncRead;
+
+use crate::codec::PacketCodec;
+use crate::error::CodecError;
+use crate::framed::FramedRead;
+use crate::Metadata;
+
+/// This stream decodes pairs of data and metadata from the wrapped
+/// `AsyncRead` of type `R`.
+///
+/// This is a thin [`FramedRead`] adapter around [`PacketCodec`]; the only
+/// thing it adds is recognizing the protocol's all-zero end-of-stream marker
+/// and turning it into the end of the `TryStream`.
+pub struct CodecStream<R> {
+    inner: FramedRead<R, PacketCodec>,
+}
+
+impl<R> CodecStream<R> {
+    /// Create a new `CodecStream`, wrapping the given reader.
+    pub fn new(reader: R) -> CodecStream<R> {
+        CodecStream {
+            inner: FramedRead::new(reader, PacketCodec::new()),
+        }
+    }
+
+    /// Consume the `CodecStream` to retrieve ownership of the inner reader.
+    pub fn into_inner(self) -> R {
+        self.inner.into_inner()
+    }
+}
+
+impl<R: AsyncRead + Unpin> TryStream for CodecStream<R> {
+    type Ok = (Box<[u8]>, Metadata);
+    type Error = CodecError;
+
+    fn try_poll_next(self: Pin<&mut Self>, wk: &Waker) -> Poll<Option<Result<Self::Ok, Self::Error>>> {
+        match Pin::new(&mut self.get_mut().inner).try_poll_next(wk) {
+            Ready(Some(Ok((data, metadata)))) => {
+                if data.is_empty() && metadata.flags == 0 && metadata.id == 0 {
+                    Ready(None)
+                } else {
+                    Ready(Some(Ok((data, metadata))))
+                }
+            }
+            other => other,
+        }
+    }
+}